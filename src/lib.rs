@@ -1,4 +1,4 @@
-use std::{error, fmt, result, str::FromStr};
+use std::{error, fmt, result, str::FromStr, net::{Ipv4Addr, Ipv6Addr}};
 
 use bytebuffer::{ByteBuffer, ByteReader};
 
@@ -21,7 +21,9 @@ impl error::Error for ParseError {}
 pub struct DnsQuery {
     pub header: DnsHeader,
     pub questions: Vec<Question>,
-    pub answers: Vec<Answer>
+    pub answers: Vec<Answer>,
+    pub authorities: Vec<Answer>,
+    pub additional: Vec<Answer>
 }
 
 impl DnsQuery {
@@ -29,7 +31,9 @@ impl DnsQuery {
         DnsQuery {
             header: DnsHeader::new(),
             questions: vec![],
-            answers: vec![]
+            answers: vec![],
+            authorities: vec![],
+            additional: vec![]
         }
     }
 
@@ -43,6 +47,18 @@ impl DnsQuery {
             result.questions.push(Question::read_buf(&mut reader)?)
         }
 
+        for _ in 0..result.header.answers {
+            result.answers.push(Answer::read_buf(&mut reader)?)
+        }
+
+        for _ in 0..result.header.authorities {
+            result.authorities.push(Answer::read_buf(&mut reader)?)
+        }
+
+        for _ in 0..result.header.additional {
+            result.additional.push(Answer::read_buf(&mut reader)?)
+        }
+
         Ok(result)
     }
 
@@ -54,6 +70,12 @@ impl DnsQuery {
         for answer in &self.answers {
             answer.write_buf(writer);
         }
+        for authority in &self.authorities {
+            authority.write_buf(writer);
+        }
+        for additional in &self.additional {
+            additional.write_buf(writer);
+        }
     }
 }
 
@@ -150,18 +172,26 @@ impl Flags {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub enum QType {
     Unknown(u16),
     A, // 1
+    NS, // 2
     CNAME, // 5
+    MX, // 15
+    TXT, // 16
+    AAAA, // 28
 }
 
 impl QType {
     fn from_u16(value: u16) -> Result<QType> {
         match value {
             1 => Ok(QType::A),
+            2 => Ok(QType::NS),
             5 => Ok(QType::CNAME),
+            15 => Ok(QType::MX),
+            16 => Ok(QType::TXT),
+            28 => Ok(QType::AAAA),
             _ => Err(Box::new(ParseError("Unknown qtype".into())))
         }
     }
@@ -169,7 +199,11 @@ impl QType {
     fn to_u16(&self) -> u16 {
         match self {
             Self::A => 1,
+            Self::NS => 2,
             Self::CNAME => 5,
+            Self::MX => 15,
+            Self::TXT => 16,
+            Self::AAAA => 28,
             Self::Unknown(v) => *v
         }
     }
@@ -181,13 +215,17 @@ impl FromStr for QType {
     fn from_str(input: &str) -> result::Result<QType, Self::Err> {
         match input {
             "A" => Ok(QType::A),
+            "NS" => Ok(QType::NS),
             "CNAME" => Ok(QType::CNAME),
+            "MX" => Ok(QType::MX),
+            "TXT" => Ok(QType::TXT),
+            "AAAA" => Ok(QType::AAAA),
             _ => Err(())
         }
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub enum QClass {
     Unknown(u16),
     IN
@@ -221,19 +259,56 @@ impl FromStr for QClass {
 }
 
 
+// RFC 1035 section 4.1.4: a label length byte with both top bits set is a
+// pointer, not a length, and its remaining 6 bits plus the next byte form a
+// 14-bit offset back into the packet.
+const POINTER_FLAG: u8 = 0xC0;
+// Bounds the number of pointer jumps a single name can take so a packet with
+// a self-referential pointer loop fails to parse instead of looping forever.
+const MAX_POINTER_JUMPS: usize = 5;
+
+fn read_qname(reader: &mut ByteReader) -> Result<String> {
+    let mut tokens = vec![];
+    let mut jumps = 0;
+    let mut resume_at: Option<usize> = None;
+
+    loop {
+        let token_size = reader.read_u8()?;
+        if token_size == 0 {
+            break;
+        }
+        if token_size & POINTER_FLAG == POINTER_FLAG {
+            if jumps >= MAX_POINTER_JUMPS {
+                return Err(Box::new(ParseError("Too many compression pointer jumps".into())));
+            }
+            let lo = reader.read_u8()?;
+            let offset = (((token_size & !POINTER_FLAG) as u16) << 8) | lo as u16;
+            if resume_at.is_none() {
+                resume_at = Some(reader.get_rpos());
+            }
+            reader.set_rpos(offset as usize);
+            jumps += 1;
+            continue;
+        }
+        let bytes = reader.read_bytes(token_size.into())?;
+        let s = std::str::from_utf8(bytes.as_slice())
+            .map_err(|e| ParseError(format!("Invalid UTF-8 sequence: {}", e)))?;
+        tokens.push(s.to_string());
+    }
+
+    if let Some(pos) = resume_at {
+        reader.set_rpos(pos);
+    }
+
+    Ok(tokens.join("."))
+}
+
 pub struct Question {
     pub qname: String,
     pub qtype: QType,
     pub qclass: QClass
 }
 
-fn none_if_zero(byte: u8) -> Option<u8> {
-    match byte {
-        0 => None,
-        size => Some(size)
-    }
-}
-
 impl Question {
     fn new() -> Question {
         Question {
@@ -245,34 +320,21 @@ impl Question {
 
     fn read_buf(reader: &mut ByteReader) -> Result<Question> {
         let mut result: Question = Question::new();
-        let mut tokens = vec![];
-        while let Some(token_size) = none_if_zero(reader.read_u8()?) {
-            let bytes = reader.read_bytes(token_size.into())?;
-            let raw = bytes.as_slice();
-            let s = match std::str::from_utf8(raw) {
-                Ok(v) => v,
-                Err(e) => panic!("Invalid UTF-8 sequence: {}", e),
-            };
-            tokens.push(s.to_string());
-        }
-        result.qname = tokens.join(".");
+        result.qname = read_qname(reader)?;
         result.qtype = QType::from_u16(reader.read_u16()?)?;
         result.qclass = QClass::from_u16(reader.read_u16()?)?;
         Ok(result)
     }
 
     pub fn write_buf(&self, writer: &mut ByteBuffer) {
-        for token in self.qname.split('.') {
-            writer.write_u8(token.len() as u8);
-            token.chars().for_each(|c| writer.write_u8(c as u8))
-        }
-        writer.write_u8(0);
+        write_name(writer, &self.qname);
         writer.write_u16(self.qtype.to_u16());
         writer.write_u16(self.qclass.to_u16());
     }
 }
 
 
+#[derive(Clone)]
 pub struct Answer {
     pub name: String,
     pub qtype: QType,
@@ -283,13 +345,41 @@ pub struct Answer {
 }
 
 impl Answer {
+    fn read_buf(reader: &mut ByteReader) -> Result<Answer> {
+        let name = read_qname(reader)?;
+        let qtype = QType::from_u16(reader.read_u16()?)?;
+        let qclass = QClass::from_u16(reader.read_u16()?)?;
+        let ttl = reader.read_u32()?;
+        let rdlength = reader.read_u16()?;
+        let rdata_start = reader.get_rpos();
+
+        // Name-bearing rdata can itself use compression pointers into the
+        // packet, so it must go through read_qname rather than being copied
+        // as opaque bytes. Re-encode it uncompressed: the pointer targets
+        // are only valid relative to the packet we read, not the one we may
+        // go on to serialize.
+        let data = match qtype {
+            QType::CNAME | QType::NS => encode_name(&read_qname(reader)?),
+            QType::MX => {
+                let preference = reader.read_u16()?;
+                let exchange = read_qname(reader)?;
+                let mut bytes = preference.to_be_bytes().to_vec();
+                bytes.extend(encode_name(&exchange));
+                bytes
+            }
+            _ => reader.read_bytes(rdlength.into())?
+        };
+
+        // Regardless of how rdata was decoded above, resume right after it
+        // as declared by rdlength so a decoding quirk can't desync the
+        // reader for whatever record follows.
+        reader.set_rpos(rdata_start + rdlength as usize);
+
+        Ok(Answer { name, qtype, qclass, ttl, length: data.len() as u16, data })
+    }
+
     pub fn write_buf(&self, writer: &mut ByteBuffer) {
-        
-        for token in self.name.split('.') {
-            writer.write_u8(token.len() as u8);
-            token.chars().for_each(|c| writer.write_u8(c as u8))
-        }
-        writer.write_u8(0);
+        write_name(writer, &self.name);
         writer.write_u16(self.qtype.to_u16());
         writer.write_u16(self.qclass.to_u16());
         writer.write_u32(self.ttl);
@@ -300,50 +390,197 @@ impl Answer {
     }
 }
 
+fn write_name(writer: &mut ByteBuffer, name: &str) {
+    for token in name.split('.') {
+        writer.write_u8(token.len() as u8);
+        token.chars().for_each(|c| writer.write_u8(c as u8))
+    }
+    writer.write_u8(0);
+}
+
+fn encode_name(name: &str) -> Vec<u8> {
+    let mut buf = ByteBuffer::new();
+    write_name(&mut buf, name);
+    buf.as_bytes().to_vec()
+}
+
+/// Encodes the wire-format rdata of a DNS record.
+// Send + Sync so a `Box<dyn RData>` inside a `DnsRecord` can live behind an
+// `Arc` shared across the UDP and TCP handler threads.
+pub trait RData: Send + Sync {
+    fn to_bytes(&self) -> Vec<u8>;
+    fn length(&self) -> usize;
+}
+
+pub struct ARdata(pub Ipv4Addr);
+
+impl RData for ARdata {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.0.octets().to_vec()
+    }
+
+    fn length(&self) -> usize {
+        4
+    }
+}
+
+pub struct AaaaRdata(pub Ipv6Addr);
+
+impl RData for AaaaRdata {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.0.octets().to_vec()
+    }
+
+    fn length(&self) -> usize {
+        16
+    }
+}
+
+pub struct NsRdata(pub String);
+
+impl RData for NsRdata {
+    fn to_bytes(&self) -> Vec<u8> {
+        encode_name(&self.0)
+    }
+
+    fn length(&self) -> usize {
+        self.to_bytes().len()
+    }
+}
+
+pub struct CnameRdata(pub String);
+
+impl RData for CnameRdata {
+    fn to_bytes(&self) -> Vec<u8> {
+        encode_name(&self.0)
+    }
+
+    fn length(&self) -> usize {
+        self.to_bytes().len()
+    }
+}
+
+pub struct MxRdata {
+    pub preference: u16,
+    pub exchange: String
+}
+
+impl RData for MxRdata {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut result = self.preference.to_be_bytes().to_vec();
+        result.extend(encode_name(&self.exchange));
+        result
+    }
+
+    fn length(&self) -> usize {
+        self.to_bytes().len()
+    }
+}
+
+pub struct TxtRdata(pub Vec<String>);
+
+impl RData for TxtRdata {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut result = vec![];
+        for s in &self.0 {
+            // Character-strings are built exclusively by chunk_txt, which
+            // already guarantees this; kept as a canary against future
+            // callers that construct a TxtRdata some other way.
+            debug_assert!(s.len() <= u8::MAX as usize, "TXT character-string exceeds the 255-byte limit: {} bytes", s.len());
+            result.push(s.len() as u8);
+            result.extend(s.bytes());
+        }
+        result
+    }
+
+    fn length(&self) -> usize {
+        self.0.iter().map(|s| s.len() + 1).sum()
+    }
+}
+
+// Splits a TXT entry into the 255-byte character-strings the wire format
+// requires, so values longer than one character-string (e.g. SPF/DKIM
+// records) can still be represented instead of being rejected outright.
+fn chunk_txt(entry: &str) -> Vec<String> {
+    const MAX_CHUNK: usize = u8::MAX as usize;
+    let mut chunks = vec![];
+    let mut rest = entry;
+    while !rest.is_empty() {
+        let mut end = rest.len().min(MAX_CHUNK);
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        chunks.push(rest[..end].to_string());
+        rest = &rest[end..];
+    }
+    chunks
+}
+
+fn build_rdata(qtype: &QType, entry: &str) -> Box<dyn RData> {
+    match qtype {
+        QType::A => {
+            let addr: Ipv4Addr = match entry.parse() {
+                Ok(addr) => addr,
+                Err(err) => panic!("Invalid A record entry: {err:?}")
+            };
+            Box::new(ARdata(addr))
+        }
+        QType::AAAA => {
+            let addr: Ipv6Addr = match entry.parse() {
+                Ok(addr) => addr,
+                Err(err) => panic!("Invalid AAAA record entry: {err:?}")
+            };
+            Box::new(AaaaRdata(addr))
+        }
+        QType::NS => Box::new(NsRdata(entry.to_string())),
+        QType::CNAME => Box::new(CnameRdata(entry.to_string())),
+        QType::MX => {
+            let (preference, exchange) = match entry.split_once(':') {
+                Some(parts) => parts,
+                None => panic!("Invalid MX record entry, expected \"<preference>:<exchange>\": {entry}")
+            };
+            let preference: u16 = match preference.parse() {
+                Ok(preference) => preference,
+                Err(err) => panic!("Invalid MX preference: {err:?}")
+            };
+            Box::new(MxRdata { preference, exchange: exchange.to_string() })
+        }
+        QType::TXT => Box::new(TxtRdata(chunk_txt(entry))),
+        QType::Unknown(value) => panic!("Cannot build rdata for unknown qtype {value}")
+    }
+}
+
 pub struct DnsRecord {
     pub qname: String,
     pub qclass: QClass,
     pub qtype: QType,
-    entry: String
+    rdata: Box<dyn RData>
 }
 
 impl DnsRecord {
     pub fn new(qname: &str, qclass: &str, qtype: &str, entry: &str) -> DnsRecord {
+        let qclass = match QClass::from_str(qclass) {
+            Ok(qclass) => qclass,
+            Err(err) => panic!("Unknown qclass: {err:?}")
+        };
+        let qtype = match QType::from_str(qtype) {
+            Ok(qtype) => qtype,
+            Err(err) => panic!("Unknown qtype: {err:?}")
+        };
+        let rdata = build_rdata(&qtype, entry);
         DnsRecord {
             qname: qname.to_string(),
-            qclass: match QClass::from_str(qclass) {
-                Ok(qclass) => qclass,
-                Err(err) => panic!("Unknown qclass: {err:?}")
-            },
-            qtype: match QType::from_str(qtype) {
-                Ok(qtype) => qtype,
-                Err(err) => panic!("Unknown qtype: {err:?}")
-            },
-            entry: entry.to_string()
+            qclass,
+            qtype,
+            rdata
         }
     }
-    
+
     pub fn length(&self) -> usize {
-        if self.qtype == QType::A {
-            4
-        } else {
-            self.entry.len() + 1
-        }
+        self.rdata.length()
     }
 
     pub fn data(&self) -> Result<Vec<u8>> {
-        let mut result: Vec<u8> = vec![];
-        if self.qtype == QType::A {
-            for byte in self.entry.split('.') {
-                result.push(byte.parse::<u8>()?)
-            }
-        } else {
-            for token in self.entry.split('.') {
-                result.push(token.len() as u8);
-                token.chars().for_each(|c| result.push(c as u8))
-            }
-            result.push(0);
-        }
-        Ok(result)
+        Ok(self.rdata.to_bytes())
     }
 }
@@ -1,12 +1,117 @@
-use std::{env, error, result, fs::File, io::{BufRead, BufReader}, net::UdpSocket};
+use std::{
+    env, error, result, fs::File,
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs, UdpSocket},
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant}
+};
 
 use bytebuffer::ByteBuffer;
-use dns_server::{Answer, DnsQuery, DnsRecord};
+use dns_server::{Answer, DnsQuery, DnsRecord, Question, QClass, QType};
 use rand::RngCore;
 
 type Error = Box<dyn error::Error>;
 type Result<T> = result::Result<T, Error>;
 
+// Upper bound on the number of distinct (qname, qtype, qclass) keys the
+// forwarding cache will hold, so a flood of distinct lookups can't grow it
+// without limit.
+const MAX_CACHE_ENTRIES: usize = 10_000;
+
+// Standard DNS-over-UDP datagram size; responses larger than this must be
+// truncated with a retry-over-TCP hint instead of sent whole.
+const MAX_UDP_MESSAGE_SIZE: usize = 512;
+
+struct CacheEntry {
+    answers: Vec<Answer>,
+    cached_at: Instant,
+    ttl: u32
+}
+
+struct Cache {
+    entries: Mutex<HashMap<(String, QType, QClass), CacheEntry>>
+}
+
+impl Cache {
+    fn new() -> Cache {
+        Cache { entries: Mutex::new(HashMap::new()) }
+    }
+
+    fn get(&self, qname: &str, qtype: &QType, qclass: &QClass) -> Option<Vec<Answer>> {
+        let key = (qname.to_string(), qtype.clone(), qclass.clone());
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get(&key)?;
+        let elapsed = entry.cached_at.elapsed().as_secs() as u32;
+        if elapsed >= entry.ttl {
+            entries.remove(&key);
+            return None;
+        }
+        let remaining_ttl = entry.ttl - elapsed;
+        Some(entry.answers.iter().cloned().map(|mut answer| {
+            answer.ttl = remaining_ttl;
+            answer
+        }).collect())
+    }
+
+    fn put(&self, qname: &str, qtype: &QType, qclass: &QClass, answers: Vec<Answer>) {
+        let ttl = match answers.iter().map(|answer| answer.ttl).min() {
+            Some(ttl) => ttl,
+            None => return
+        };
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= MAX_CACHE_ENTRIES {
+            // HashMap iteration order is arbitrary, so this evicts a
+            // random entry rather than the oldest/least-recently-used one.
+            // Good enough to bound memory use; an actual LRU would need to
+            // track access order separately.
+            if let Some(evict_key) = entries.keys().next().cloned() {
+                entries.remove(&evict_key);
+            }
+        }
+        let key = (qname.to_string(), qtype.clone(), qclass.clone());
+        entries.insert(key, CacheEntry { answers, cached_at: Instant::now(), ttl });
+    }
+}
+
+
+fn parse_args(args: &[String]) -> (String, Option<String>) {
+    let mut file_path = None;
+    let mut forward = None;
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--forward" => {
+                i += 1;
+                forward = args.get(i).cloned();
+            }
+            other => file_path = Some(other.to_string())
+        }
+        i += 1;
+    }
+    (file_path.expect("Missing zone file path"), forward)
+}
+
+// Splits a zone-file line into its qname/qclass/qtype fields plus an entry
+// field that runs to the end of the line, so entries like a TXT value can
+// contain internal spaces (`v=spf1 include:_spf.example.com ~all`) instead
+// of being treated as extra whitespace-separated fields.
+fn split_zone_line(line: &str) -> Option<(String, String, String, String)> {
+    let mut rest = line;
+    let mut fields: Vec<&str> = Vec::with_capacity(3);
+    for _ in 0..3 {
+        rest = rest.trim_start();
+        let end = rest.find(char::is_whitespace)?;
+        fields.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+    let entry = rest.trim();
+    if entry.is_empty() {
+        return None;
+    }
+    Some((fields[0].to_string(), fields[1].to_string(), fields[2].to_string(), entry.to_string()))
+}
 
 fn parse_file(file_path: &str) -> Vec<DnsRecord> {
     let file = match File::open(file_path) {
@@ -20,47 +125,124 @@ fn parse_file(file_path: &str) -> Vec<DnsRecord> {
             Ok(line) => line,
             Err(err) => panic!("Failed to read file: {err:?}")
         };
-        let splitted: Vec<String> = line.split_whitespace().map(|s| s.to_string()).collect();
-        if splitted.len() != 4 {
-            continue;
-        }
-        let record = DnsRecord::new(
-            &splitted[0],
-            &splitted[1],
-            &splitted[2],
-            &splitted[3]
-        );
+        let (qname, qclass, qtype, entry) = match split_zone_line(&line) {
+            Some(fields) => fields,
+            None => continue
+        };
+        let record = DnsRecord::new(&qname, &qclass, &qtype, &entry);
         records.push(record);
     }
     records
 }
 
-fn handle(socket: &UdpSocket, records: &Vec<DnsRecord>) -> Result<()> {
-    let mut buf = [0u8; 512];
-    let (_, from) = socket.recv_from(&mut buf)?;
-    let query = DnsQuery::from_buffer(&buf)?;
-    
+// Bounds how long a single forwarded lookup may block the caller, so an
+// upstream that drops packets instead of answering can't wedge the whole
+// server.
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(2);
+
+fn forward_query(forward_addr: &str, question: &Question) -> Result<Vec<Answer>> {
+    let upstream = match forward_addr.to_socket_addrs()?.next() {
+        Some(addr) => addr,
+        None => return Ok(vec![])
+    };
+
+    let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+
+    let mut query = DnsQuery::new();
+    let query_id = rand::thread_rng().next_u32() as u16;
+    query.header.id = query_id;
+    query.header.questions = 1;
+    query.header.flags.recursion_desired = true;
+    query.questions.push(Question {
+        qname: question.qname.clone(),
+        qtype: question.qtype.clone(),
+        qclass: question.qclass.clone()
+    });
+
+    let mut buf = ByteBuffer::new();
+    query.write_buf(&mut buf);
+    socket.send_to(buf.as_bytes(), upstream)?;
+
+    // Keep reading until we see a reply that actually answers our query
+    // (matching id, from the upstream we queried) instead of trusting the
+    // first datagram to land on this ephemeral port, so a stray or spoofed
+    // packet can't get cached and served to a client. Bounded by
+    // FORWARD_TIMEOUT overall, not per read, so a stream of junk can't
+    // reset the clock and hang the caller indefinitely.
+    let deadline = Instant::now() + FORWARD_TIMEOUT;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Ok(vec![]);
+        }
+        socket.set_read_timeout(Some(remaining))?;
+
+        let mut response_buf = [0u8; 512];
+        let (_, from) = match socket.recv_from(&mut response_buf) {
+            Ok(result) => result,
+            Err(_) => return Ok(vec![])
+        };
+        if from != upstream {
+            continue;
+        }
+        let response = match DnsQuery::from_buffer(&response_buf) {
+            Ok(response) => response,
+            Err(_) => continue
+        };
+        if response.header.id != query_id {
+            continue;
+        }
+        return Ok(response.answers);
+    }
+}
+
+fn build_response(query: DnsQuery, records: &Vec<DnsRecord>, forward: &Option<String>, cache: &Cache) -> Result<DnsQuery> {
     let mut response = DnsQuery::new();
+    // A question can legitimately be answered by more than one record (a
+    // forwarded lookup can return several A records, a CNAME chain, etc.),
+    // so the response code can't be derived from comparing answer/question
+    // counts -- track which questions actually got an answer instead.
+    let mut unanswered_questions = 0;
     for question in &query.questions {
         let matched_records: Vec<&DnsRecord> = records.iter()
             .filter(|record| record.qtype == question.qtype)
             .filter(|record| record.qclass == question.qclass)
             .filter(|record| record.qname == question.qname)
             .collect();
-        if matched_records.is_empty() {
+        if !matched_records.is_empty() {
+            let mut rng = rand::thread_rng();
+            let index = rng.next_u32() % matched_records.len() as u32;
+            let record = matched_records[index as usize];
+            response.answers.push(Answer {
+                name: record.qname.clone(),
+                qclass: record.qclass.clone(),
+                qtype: record.qtype.clone(),
+                ttl: 60,
+                length: record.length() as u16,
+                data: record.data()?
+            });
             continue;
         }
-        let mut rng = rand::thread_rng();
-        let index = rng.next_u32() % matched_records.len() as u32;
-        let record = matched_records[index as usize];
-        response.answers.push(Answer {
-            name: record.qname.clone(),
-            qclass: record.qclass.clone(),
-            qtype: record.qtype.clone(),
-            ttl: 60,
-            length: record.length() as u16,
-            data: record.data()?
-        })
+
+        let answers = match forward {
+            Some(forward_addr) => match cache.get(&question.qname, &question.qtype, &question.qclass) {
+                Some(cached) => cached,
+                None => {
+                    let answers = forward_query(forward_addr, question)?;
+                    if !answers.is_empty() {
+                        cache.put(&question.qname, &question.qtype, &question.qclass, answers.clone());
+                    }
+                    answers
+                }
+            },
+            None => vec![]
+        };
+
+        if answers.is_empty() {
+            unanswered_questions += 1;
+        } else {
+            response.answers.extend(answers);
+        }
     }
     response.questions = query.questions;
     response.header = query.header;
@@ -70,27 +252,124 @@ fn handle(socket: &UdpSocket, records: &Vec<DnsRecord>) -> Result<()> {
     response.header.flags.qr = true;
     response.header.flags.authorihative_answer = false;
     response.header.flags.truncate = false;
-    response.header.flags.recursion_available = false;
-    response.header.flags.response_code = if response.header.answers != response.header.questions {3} else { 0 };
+    response.header.flags.recursion_available = forward.is_some();
+    response.header.flags.response_code = if unanswered_questions > 0 { 3 } else { 0 };
 
-    let mut buf = ByteBuffer::new();
-    response.write_buf(&mut buf);
+    Ok(response)
+}
+
+fn handle_udp_datagram(socket: &UdpSocket, buf: &[u8], from: SocketAddr, records: &Vec<DnsRecord>, forward: &Option<String>, cache: &Cache) -> Result<()> {
+    let query = DnsQuery::from_buffer(buf)?;
+    let response = build_response(query, records, forward, cache)?;
+
+    let mut out = ByteBuffer::new();
+    response.write_buf(&mut out);
 
-    socket.send_to(buf.as_bytes(), from)?;
+    if out.as_bytes().len() > MAX_UDP_MESSAGE_SIZE {
+        let mut truncated = DnsQuery::new();
+        truncated.header = response.header;
+        truncated.header.flags.truncate = true;
+        truncated.header.answers = 0;
+        truncated.questions = response.questions;
+
+        out = ByteBuffer::new();
+        truncated.write_buf(&mut out);
+    }
+
+    socket.send_to(out.as_bytes(), from)?;
     Ok(())
 }
 
+// Reads one datagram on the caller's thread, then hands the actual lookup
+// (which may block on a slow/unanswered forwarded query) to a worker
+// thread, so one stalled client can't delay every other UDP client behind
+// it in the same way the TCP listener already spawns a thread per
+// connection.
+fn serve_udp(socket: Arc<UdpSocket>, records: Arc<Vec<DnsRecord>>, forward: Arc<Option<String>>, cache: Arc<Cache>) {
+    loop {
+        let mut buf = [0u8; MAX_UDP_MESSAGE_SIZE];
+        let (len, from) = match socket.recv_from(&mut buf) {
+            Ok(result) => result,
+            Err(err) => {
+                eprintln!("Failed to receive UDP datagram: {err}");
+                continue;
+            }
+        };
+
+        let socket = Arc::clone(&socket);
+        let records = Arc::clone(&records);
+        let forward = Arc::clone(&forward);
+        let cache = Arc::clone(&cache);
+        thread::spawn(move || {
+            if let Err(err) = handle_udp_datagram(&socket, &buf[..len], from, &records, &forward, &cache) {
+                eprintln!("Failed to handle UDP request: {err}");
+            }
+        });
+    }
+}
+
+fn handle_tcp_connection(mut stream: TcpStream, records: &Vec<DnsRecord>, forward: &Option<String>, cache: &Cache) -> Result<()> {
+    loop {
+        let mut length_prefix = [0u8; 2];
+        if stream.read_exact(&mut length_prefix).is_err() {
+            return Ok(());
+        }
+        let mut buf = vec![0u8; u16::from_be_bytes(length_prefix) as usize];
+        stream.read_exact(&mut buf)?;
+
+        let query = DnsQuery::from_buffer(&buf)?;
+        let response = build_response(query, records, forward, cache)?;
+
+        let mut out = ByteBuffer::new();
+        response.write_buf(&mut out);
+
+        stream.write_all(&(out.as_bytes().len() as u16).to_be_bytes())?;
+        stream.write_all(out.as_bytes())?;
+    }
+}
+
+fn serve_tcp(listener: TcpListener, records: Arc<Vec<DnsRecord>>, forward: Arc<Option<String>>, cache: Arc<Cache>) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!("Failed to accept TCP connection: {err}");
+                continue;
+            }
+        };
+        let records = Arc::clone(&records);
+        let forward = Arc::clone(&forward);
+        let cache = Arc::clone(&cache);
+        thread::spawn(move || {
+            if let Err(err) = handle_tcp_connection(stream, &records, &forward, &cache) {
+                eprintln!("Failed to handle TCP connection: {err}");
+            }
+        });
+    }
+}
+
 fn main(){
     let args: Vec<String> = env::args().collect();
-    let file_path = &args[1];
-    let records = parse_file(file_path);
+    let (file_path, forward) = parse_args(&args);
+    let records = Arc::new(parse_file(&file_path));
+    let forward = Arc::new(forward);
+    let cache = Arc::new(Cache::new());
 
     let socket = match UdpSocket::bind(("0.0.0.0", 5353)) {
-        Ok(socket) => socket,
-        Err(err) => panic!("Failed to open socket: {err:?}"),
+        Ok(socket) => Arc::new(socket),
+        Err(err) => panic!("Failed to open UDP socket: {err:?}"),
+    };
+    let tcp_listener = match TcpListener::bind(("0.0.0.0", 5353)) {
+        Ok(listener) => listener,
+        Err(err) => panic!("Failed to open TCP listener: {err:?}"),
     };
 
-    loop {
-        handle(&socket, &records).expect("fail to handle");
+    {
+        let records = Arc::clone(&records);
+        let forward = Arc::clone(&forward);
+        let cache = Arc::clone(&cache);
+        thread::spawn(move || serve_tcp(tcp_listener, records, forward, cache));
     }
+
+    serve_udp(socket, records, forward, cache);
 }